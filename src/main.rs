@@ -1,4 +1,4 @@
-use chrono::{Datelike, NaiveDate};
+use chrono::{Datelike, Months, NaiveDate};
 use clap::Parser;
 use faccess::{AccessMode, PathExt};
 use rusqlite::{Connection, OpenFlags /*, Result*/};
@@ -22,14 +22,64 @@ struct Args {
     #[arg(short, long)]
     end_date: Option<String>,
 
-    /// Process full month from current year. Accepted values are numeric or Jan/January/Ene/Enero, etc
+    /// Process a full month. Accepted values are numeric or a localized month name
+    /// (Jan/January/Ene/Enero, etc), taken from the current year, or a relative keyword
+    /// (this/current, last/prev/anterior) resolved against today's date
     //#[arg(short, long, allow_negative_numbers = true)] --> trick to allow negative numbers in CLI options
     #[arg(short, long)]
     month: Option<String>,
 
+    /// Process a range spanning the last N months (ending with last month), instead of a single month
+    #[arg(long)]
+    last_n_months: Option<u32>,
+
+    /// Process a specific quarter (1-4) of the current year, or of --year if also given
+    #[arg(long)]
+    quarter: Option<u32>,
+
+    /// Process a full year (Jan 1 - Dec 31). Also selects the year for --quarter
+    #[arg(long)]
+    year: Option<i32>,
+
     /// Increase program debug messages. Can be specified multiple times
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: Option<u8>,
+
+    /// Output format for the exported rows
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+
+    /// Field delimiter for the csv/tsv formats (default: ';' for csv, tab for tsv)
+    #[arg(long)]
+    delimiter: Option<char>,
+
+    /// Decimal separator used for amounts in the csv/tsv output
+    #[arg(long, default_value_t = ',')]
+    decimal_separator: char,
+
+    /// Print per-category and per-payment-method subtotals instead of the flat transaction list
+    #[arg(long)]
+    summary: bool,
+
+    /// Transaction type to export
+    #[arg(long = "type", value_enum, default_value_t = TransactionType::Expense)]
+    transaction_type: TransactionType,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputFormat {
+    #[default]
+    Csv,
+    Tsv,
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum TransactionType {
+    #[default]
+    Expense,
+    Income,
+    All,
 }
 
 // A processed version of Args. I don't want Option<T> all over the place.
@@ -39,37 +89,45 @@ struct Config {
     start_date: String,
     end_date: String,
     debug_level: u8,
+    format: OutputFormat,
+    delimiter: char,
+    decimal_separator: char,
+    summary: bool,
+    transaction_type: TransactionType,
+}
+
+fn beginning_of_month(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).expect("day 1 must always be a valid date")
+}
+
+// First day of `start`'s month, advanced by `n_months`, then back one day: the last day of the
+// n_months-wide range starting at `start`. Using chrono's calendar arithmetic instead of manual
+// day counting means this clamps correctly across year boundaries and leap years.
+fn end_of_range(start: NaiveDate, n_months: u32) -> NaiveDate {
+    beginning_of_month(start)
+        .checked_add_months(Months::new(n_months))
+        .expect("month arithmetic must not overflow")
+        - chrono::Duration::days(1)
 }
 
-// Based on https://stackoverflow.com/questions/53687045/how-to-get-the-number-of-days-in-a-month-in-rust,
-// but using from_ymd_opt() rather than the deprecated from_ymd() in chrono-0.4.23
-pub fn get_days_from_month(year: i32, month: u32) -> u32 {
-    NaiveDate::from_ymd_opt(
-        match month {
-            12 => year + 1,
-            _ => year,
-        },
-        match month {
-            12 => 1,
-            _ => month + 1,
-        },
-        1,
-    )
-    .expect("Date constructed must be valid")
-    .signed_duration_since(NaiveDate::from_ymd_opt(year, month, 1).unwrap())
-    .num_days()
-    .try_into()
-    .expect("Converted an i64 into i32, but num_days() must always be <= 31")
-}
-
-fn get_query_statement() -> String {
+fn end_of_month(date: NaiveDate) -> NaiveDate {
+    end_of_range(date, 1)
+}
+
+fn get_query_statement(transaction_type: TransactionType) -> String {
     let mut str_query = String::from("");
-    str_query.push_str("SELECT z.zdate, z.ztxdatestr, c.zname, z.zcontent, z.zamount, a.znicname ");
+    str_query.push_str(
+        "SELECT z.zdate, z.ztxdatestr, c.zname, z.zcontent, z.zamount, a.znicname, z.zdo_type ",
+    );
     str_query.push_str("FROM ZASSET a, ZCATEGORY c, ZINOUTCOME z ");
     str_query.push_str("WHERE z.ztxdatestr ");
     str_query.push_str("BETWEEN ?1 AND ?2 "); // Begin and end dates
     str_query.push_str("AND z.zisdel = 0 "); // zisdel flags deleted entries
-    str_query.push_str("AND z.zdo_type = 1 "); // Type 1 is "expenses")
+    match transaction_type {
+        TransactionType::Expense => str_query.push_str("AND z.zdo_type = 1 "), // Type 1 is "expenses"
+        TransactionType::Income => str_query.push_str("AND z.zdo_type = 2 "), // Type 2 is "income"
+        TransactionType::All => {} // No filter: both expenses and income
+    }
     str_query.push_str("AND z.ZASSETUID = a.ZUID "); // Join asset (pay method))
     str_query.push_str("AND z.ZCATEGORYUID = c.ZUID "); // Join Category
     str_query.push_str("ORDER BY z.zdate ASC");
@@ -77,7 +135,16 @@ fn get_query_statement() -> String {
     str_query
 }
 
-fn parse_month(month: &Option<String>) -> Option<u8> {
+// A month, as selected on the command line: either an explicit calendar month, or a relative
+// keyword resolved against "now" at the call site (see `init_config`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MonthSpec {
+    This,
+    Last,
+    Month(u32),
+}
+
+fn parse_month(month: &Option<String>) -> Option<MonthSpec> {
     let month_str: &String;
 
     if month.is_none() {
@@ -88,6 +155,12 @@ fn parse_month(month: &Option<String>) -> Option<u8> {
         //println!("Month in command line: {}", month_str);
     }
 
+    match month_str.to_lowercase().as_str() {
+        "this" | "current" => return Some(MonthSpec::This),
+        "last" | "prev" | "anterior" => return Some(MonthSpec::Last),
+        _ => {}
+    }
+
     let mut months = HashMap::new();
     months.insert("jan", 1);
     months.insert("january", 1);
@@ -136,14 +209,16 @@ fn parse_month(month: &Option<String>) -> Option<u8> {
             .get(month_str.to_lowercase().as_str())
             .copied()
             .unwrap();
-        return Some(month_index);
+        return Some(MonthSpec::Month(month_index));
     }
 
     // Second, try to obtain a month from a number
     // Nice way to transform a Result<> into an Option<>
-    month_str.parse::<u8>()
+    month_str
+        .parse::<u32>()
         .ok()
         .filter(|v| *v >= 1 && *v <= 12)
+        .map(MonthSpec::Month)
 }
 fn process_category(category: String) -> String {
     // As of this writing there seems to be no more 'category/sub-category', only 'category'
@@ -155,16 +230,17 @@ fn process_name(name: String) -> String {
     name.trim().to_string()
 }
 
-// Transform float "x.y" into String "x,y".
-fn process_amount(amount: f64) -> String {
-    //let mut amt_str = amount.to_string();
-    let integer_part = amount.floor().to_string();
-    let decimal_part = format!("{:02}", (100.0 * amount.fract()).round());
-    //println!("f32: {}, integer: {}, decimal: {}", amount, integer_part, decimal_part);
-
-    let amt_str = integer_part + "," + &decimal_part;
-
-    amt_str
+// Transform float "x.y" into String "x<decimal_separator>yy".
+fn format_amount(amount: f64, decimal_separator: char) -> String {
+    // Round to whole cents first, so a sum like 30.999999999999996 becomes "31,00" rather than
+    // carrying a rounded-up fraction of 100 into the decimal part.
+    let cents = (amount * 100.0).round();
+    let sign = if cents < 0.0 { "-" } else { "" };
+    let cents = cents.abs() as u64;
+    let integer_part = cents / 100;
+    let decimal_part = format!("{:02}", cents % 100);
+
+    format!("{sign}{integer_part}{decimal_separator}{decimal_part}")
 }
 
 fn process_date(date: String) -> String {
@@ -172,6 +248,29 @@ fn process_date(date: String) -> String {
     parts.join("/")
 }
 
+// Distinguishes expense from income rows now that `--type all` can mix both in one export.
+fn process_transaction_type(zdo_type: i64) -> String {
+    match zdo_type {
+        1 => "Gasto".to_string(),
+        2 => "Ingreso".to_string(),
+        _ => "INVALID".to_string(),
+    }
+}
+
+// `zamount` is stored as an unsigned magnitude regardless of type. When rows of both types can
+// appear in the same export (`--type all`), a running total needs expenses to subtract rather
+// than add, or the total is not a meaningful net figure. `--type expense`/`--type income` only
+// ever see one sign of transaction, so they keep summing unsigned magnitudes as before.
+fn signed_amount(amount: f64, zdo_type: i64, transaction_type: TransactionType) -> f64 {
+    if transaction_type != TransactionType::All {
+        return amount;
+    }
+    match zdo_type {
+        1 => -amount, // Expense
+        _ => amount,  // Income, and any other type left unchanged
+    }
+}
+
 fn process_payment_method(pay_method: String) -> String {
     let ret_pay_method: String;
     match pay_method.as_str() {
@@ -185,10 +284,196 @@ fn process_payment_method(pay_method: String) -> String {
     }
     ret_pay_method
 }
+// Row-rendering strategy for `query_and_print`, decoupled from the SQL/iteration logic so the
+// tool can emit CSV, TSV or JSON without duplicating the query loop.
+trait Formatter {
+    fn write_header(&self);
+    fn write_row(
+        &self,
+        date: &str,
+        category: &str,
+        name: &str,
+        amount: f64,
+        pay_method: &str,
+        tx_type: &str,
+    );
+    fn finalize(&self, total: f64);
+}
+
+struct CsvFormatter {
+    delimiter: char,
+    decimal_separator: char,
+}
+
+impl CsvFormatter {
+    fn new(delimiter: char, decimal_separator: char) -> Self {
+        Self {
+            delimiter,
+            decimal_separator,
+        }
+    }
+}
+
+impl Formatter for CsvFormatter {
+    fn write_header(&self) {
+        println!(
+            "fecha{0}categoría{0}comentario{0}importe{0}forma pago{0}tipo",
+            self.delimiter
+        );
+    }
+
+    fn write_row(
+        &self,
+        date: &str,
+        category: &str,
+        name: &str,
+        amount: f64,
+        pay_method: &str,
+        tx_type: &str,
+    ) {
+        println!(
+            "{1}{0}{2}{0}{3}{0}{4}{0}{5}{0}{6}",
+            self.delimiter,
+            date,
+            category,
+            name,
+            format_amount(amount, self.decimal_separator),
+            pay_method,
+            tx_type
+        );
+    }
+
+    fn finalize(&self, total: f64) {
+        println!("Total: {}", format_amount(total, self.decimal_separator));
+    }
+}
+
+// TSV is CSV with a tab delimiter by default; kept as its own type so `--format tsv` reads as a
+// first-class choice rather than a CSV flag combination.
+struct TsvFormatter {
+    inner: CsvFormatter,
+}
+
+impl TsvFormatter {
+    fn new(delimiter: char, decimal_separator: char) -> Self {
+        Self {
+            inner: CsvFormatter::new(delimiter, decimal_separator),
+        }
+    }
+}
+
+impl Formatter for TsvFormatter {
+    fn write_header(&self) {
+        self.inner.write_header();
+    }
+
+    fn write_row(
+        &self,
+        date: &str,
+        category: &str,
+        name: &str,
+        amount: f64,
+        pay_method: &str,
+        tx_type: &str,
+    ) {
+        self.inner
+            .write_row(date, category, name, amount, pay_method, tx_type);
+    }
+
+    fn finalize(&self, total: f64) {
+        self.inner.finalize(total);
+    }
+}
+
+// Escapes the characters that would otherwise break a JSON string literal, including raw control
+// characters (e.g. a newline embedded in a transaction comment) that JSON forbids unescaped.
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Buffers rows and emits a single JSON object on `finalize`, so scripting pipelines get one
+// well-formed document instead of a stream that a trailing "Total:" line would corrupt.
+struct JsonFormatter {
+    rows: std::cell::RefCell<Vec<String>>,
+}
+
+impl JsonFormatter {
+    fn new() -> Self {
+        Self {
+            rows: std::cell::RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Formatter for JsonFormatter {
+    fn write_header(&self) {}
+
+    fn write_row(
+        &self,
+        date: &str,
+        category: &str,
+        name: &str,
+        amount: f64,
+        pay_method: &str,
+        tx_type: &str,
+    ) {
+        self.rows.borrow_mut().push(format!(
+            "{{\"date\": \"{}\", \"category\": \"{}\", \"name\": \"{}\", \"amount\": {}, \"pay_method\": \"{}\", \"type\": \"{}\"}}",
+            json_escape(date),
+            json_escape(category),
+            json_escape(name),
+            amount,
+            json_escape(pay_method),
+            json_escape(tx_type)
+        ));
+    }
+
+    fn finalize(&self, total: f64) {
+        println!(
+            "{{\"transactions\": [{}], \"total\": {}}}",
+            self.rows.borrow().join(", "),
+            total
+        );
+    }
+}
+
+fn make_formatter(config: &Config) -> Box<dyn Formatter> {
+    match config.format {
+        OutputFormat::Csv => Box::new(CsvFormatter::new(config.delimiter, config.decimal_separator)),
+        OutputFormat::Tsv => Box::new(TsvFormatter::new(config.delimiter, config.decimal_separator)),
+        OutputFormat::Json => Box::new(JsonFormatter::new()),
+    }
+}
+
+// Prints a totals table sorted descending by amount, e.g. category -> total or payment method ->
+// total, mirroring the grouped-entries display of similar expense trackers.
+fn print_summary_table(title: &str, totals: &HashMap<String, f64>, decimal_separator: char) {
+    let mut entries: Vec<(&String, &f64)> = totals.iter().collect();
+    entries.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+
+    println!("{title}");
+    for (key, amount) in entries {
+        println!("{key}: {}", format_amount(*amount, decimal_separator));
+    }
+    println!();
+}
+
 fn query_and_print(config: &Config) {
     let conn =
         Connection::open_with_flags(&config.file_name, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
-    let str_query = get_query_statement();
+    let str_query = get_query_statement(config.transaction_type);
     //    println!("strquery: '{}'", &str_query);
     let mut stmt = conn.prepare(&str_query).unwrap();
 
@@ -197,7 +482,32 @@ fn query_and_print(config: &Config) {
 
     let mut rows = stmt.query([&config.start_date, &config.end_date]).unwrap();
 
-    println!("fecha;categoría;comentario;importe;forma pago");
+    if config.summary {
+        let mut by_category: HashMap<String, f64> = HashMap::new();
+        let mut by_pay_method: HashMap<String, f64> = HashMap::new();
+        let mut tot_amt: f64 = 0.0;
+
+        while let Some(row) = rows.next().unwrap() {
+            let category: String = process_category(row.get_unwrap(2));
+            let amount: f64 = row.get_unwrap(4);
+            let pay_method: String = process_payment_method(row.get_unwrap(5));
+            let signed_amt = signed_amount(amount, row.get_unwrap(6), config.transaction_type);
+
+            *by_category.entry(category).or_insert(0.0) += signed_amt;
+            *by_pay_method.entry(pay_method).or_insert(0.0) += signed_amt;
+            tot_amt += signed_amt;
+        }
+
+        print_summary_table("Categoría", &by_category, config.decimal_separator);
+        print_summary_table("Forma de pago", &by_pay_method, config.decimal_separator);
+        println!("Total: {}", format_amount(tot_amt, config.decimal_separator));
+
+        return;
+    }
+
+    let formatter = make_formatter(config);
+
+    formatter.write_header();
     let mut tot_amt: f64 = 0.0;
     while let Some(row) = rows.next().unwrap() {
         //println!("{}", row.get_unwrap(0));
@@ -205,14 +515,16 @@ fn query_and_print(config: &Config) {
         let date: String = process_date(row.get_unwrap(1));
         let category: String = process_category(row.get_unwrap(2));
         let name: String = process_name(row.get_unwrap(3));
-        let amt: String = process_amount(row.get_unwrap(4));
+        let amount: f64 = row.get_unwrap(4);
         let pay_method: String = process_payment_method(row.get_unwrap(5));
+        let zdo_type: i64 = row.get_unwrap(6);
+        let tx_type: String = process_transaction_type(zdo_type);
 
-        println!("{};{};{};{};{}", date, category, name, amt, pay_method);
-        tot_amt += row.get_unwrap::<usize, f64>(4);
+        formatter.write_row(&date, &category, &name, amount, &pay_method, &tx_type);
+        tot_amt += signed_amount(amount, zdo_type, config.transaction_type);
     }
 
-    println!("Total: {:.2}", tot_amt);
+    formatter.finalize(tot_amt);
 
     //    conn.close();
 }
@@ -221,6 +533,14 @@ fn init_config(args: &Args, config: &mut Config) {
     // Non-date of config params
     config.file_name = args.file_name.clone();
     config.debug_level = args.debug.unwrap_or(0);
+    config.format = args.format;
+    config.delimiter = args.delimiter.unwrap_or(match config.format {
+        OutputFormat::Tsv => '\t',
+        OutputFormat::Csv | OutputFormat::Json => ';',
+    });
+    config.decimal_separator = args.decimal_separator;
+    config.summary = args.summary;
+    config.transaction_type = args.transaction_type;
 
     // Basic check on database file
     let database_path = Path::new(&config.file_name);
@@ -229,24 +549,76 @@ fn init_config(args: &Args, config: &mut Config) {
         std::process::exit(1);
     }
     // Date config params
-    let month_opt = parse_month(&args.month);
-    if month_opt.is_some() {
+    let today = chrono::Utc::now().date_naive();
+
+    if (args.quarter.is_some() || args.year.is_some())
+        && (args.month.is_some()
+            || args.last_n_months.is_some()
+            || args.start_date.is_some()
+            || args.end_date.is_some())
+    {
+        eprintln!(
+            "--quarter/--year cannot be combined with --month, --last-n-months, --start-date or --end-date"
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(q) = args.quarter {
+        if !(1..=4).contains(&q) {
+            eprintln!("--quarter must be between 1 and 4");
+            std::process::exit(1);
+        }
+        let year = args.year.unwrap_or_else(|| today.year());
+        let quarter_start = NaiveDate::from_ymd_opt(year, (q - 1) * 3 + 1, 1).unwrap();
+
+        config.start_date = quarter_start.to_string();
+        config.end_date = end_of_range(quarter_start, 3).to_string();
+
+        return;
+    }
+
+    if let Some(year) = args.year {
+        let year_start = NaiveDate::from_ymd_opt(year, 1, 1).unwrap();
+
+        config.start_date = year_start.to_string();
+        config.end_date = end_of_range(year_start, 12).to_string();
+
+        return;
+    }
+
+    let month_spec = parse_month(&args.month);
+    if let Some(spec) = month_spec {
         //println!("Parsing month");
         // We have a month, let it take priority
-        let end_day = get_days_from_month(chrono::Utc::now().year(), month_opt.unwrap().into());
-
-        let start_date =
-            NaiveDate::from_ymd_opt(chrono::Utc::now().year(), month_opt.unwrap().into(), 1)
-                .unwrap();
-        config.start_date = start_date.to_string().clone();
-
-        let end_date = NaiveDate::from_ymd_opt(
-            chrono::Utc::now().year(),
-            month_opt.unwrap().into(),
-            end_day,
-        )
-        .unwrap();
-        config.end_date = end_date.to_string();
+        let month_start = match spec {
+            MonthSpec::This => beginning_of_month(today),
+            MonthSpec::Last => beginning_of_month(today)
+                .checked_sub_months(Months::new(1))
+                .expect("month arithmetic must not overflow"),
+            MonthSpec::Month(m) => NaiveDate::from_ymd_opt(today.year(), m, 1).unwrap(),
+        };
+
+        config.start_date = month_start.to_string();
+        config.end_date = end_of_month(month_start).to_string();
+
+        return;
+    }
+
+    if let Some(n) = args.last_n_months {
+        if n == 0 {
+            eprintln!("--last-n-months must be at least 1");
+            std::process::exit(1);
+        }
+        // Anchor the range on last month, matching the tool's default single-month behavior.
+        let end_month_start = beginning_of_month(today)
+            .checked_sub_months(Months::new(1))
+            .expect("month arithmetic must not overflow");
+        let start_month_start = end_month_start
+            .checked_sub_months(Months::new(n - 1))
+            .expect("month arithmetic must not overflow");
+
+        config.start_date = start_month_start.to_string();
+        config.end_date = end_of_range(start_month_start, n).to_string();
 
         return;
     }
@@ -257,17 +629,9 @@ fn init_config(args: &Args, config: &mut Config) {
     let parsed_end_date;
     if args.start_date.is_none() {
         // No month, no start date => use last month for start_date
-        let start_date;
-        if chrono::Utc::now().month() == 1 {
-            start_date = NaiveDate::from_ymd_opt(chrono::Utc::now().year() - 1, 12, 1).unwrap();
-        } else {
-            start_date = NaiveDate::from_ymd_opt(
-                chrono::Utc::now().year(),
-                chrono::Utc::now().month() - 1,
-                1,
-            )
-            .unwrap();
-        }
+        let start_date = beginning_of_month(today)
+            .checked_sub_months(Months::new(1))
+            .expect("month arithmetic must not overflow");
         config.start_date = start_date.to_string();
     } else {
         config.start_date = args.start_date.as_ref().unwrap().clone();
@@ -287,20 +651,9 @@ fn init_config(args: &Args, config: &mut Config) {
     config.start_date = parsed_start_date.unwrap().to_string();
 
     if args.end_date.is_none() {
-        // No end date: use the last day of config.start_date (already set above)
-        let end_date;
-
+        // No end date: use the last day of config.start_date's month (already set above)
         assert!(parsed_start_date.is_ok());
-        let num_days_in_month = get_days_from_month(
-            parsed_start_date.unwrap().year(),
-            parsed_start_date.unwrap().month(),
-        );
-        end_date = NaiveDate::from_ymd_opt(
-            parsed_start_date.unwrap().year(),
-            parsed_start_date.unwrap().month(),
-            num_days_in_month,
-        )
-        .unwrap();
+        let end_date = end_of_month(parsed_start_date.unwrap());
         config.end_date = end_date.to_string();
     } else {
         config.end_date = args.end_date.as_ref().unwrap().clone();